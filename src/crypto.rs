@@ -0,0 +1,357 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use rand_core::OsRng;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+pub const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Length in bytes of an X25519 public key, as exchanged during a
+/// `CryptoMethod::Noise` handshake.
+pub const HANDSHAKE_KEY_LEN: usize = 32;
+
+/// Re-run the handshake after this many transport messages, discarding the
+/// old keys so a later key compromise cannot decrypt past traffic.
+const REKEY_AFTER_MESSAGES: u64 = 60_000;
+/// ...or after this much wall-clock time, whichever comes first.
+const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
+
+/// Width of the anti-replay sliding window, in packets. A received sequence
+/// number that falls this far or further behind the highest one seen so far
+/// is rejected outright instead of being checked against the bitmap.
+const REPLAY_WINDOW_SIZE: u64 = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CryptoMethod {
+    ChaCha20,
+    AES256,
+    /// Ephemeral X25519 handshake with HKDF-derived ChaCha20-Poly1305
+    /// transport keys, giving forward secrecy instead of a static key.
+    Noise,
+}
+
+/// Sliding-window replay filter as described in RFC 6479.
+///
+/// `last_seq` is the highest sequence number accepted so far and `bitmap`
+/// tracks which of the `REPLAY_WINDOW_SIZE` sequence numbers ending at it
+/// have already been seen (bit 0 corresponds to `last_seq` itself, bit `n`
+/// to `last_seq - n`).
+#[derive(Debug)]
+pub struct ReplayFilter {
+    last_seq: u64,
+    bitmap: u64,
+}
+
+impl ReplayFilter {
+    pub fn new() -> Self {
+        ReplayFilter { last_seq: 0, bitmap: 0 }
+    }
+
+    /// Checks whether `seq` is an acceptable, not-yet-seen sequence number
+    /// and, if so, records it. Returns `false` when the packet must be
+    /// rejected as a replay or as too old to fall inside the window.
+    pub fn check_and_update(&mut self, seq: u64) -> bool {
+        if self.last_seq == 0 {
+            // First packet ever seen on this filter, accept unconditionally.
+            self.last_seq = seq;
+            self.bitmap = 1;
+            return true;
+        }
+        if seq > self.last_seq {
+            let diff = seq - self.last_seq;
+            if diff >= REPLAY_WINDOW_SIZE {
+                self.bitmap = 0;
+            } else {
+                self.bitmap <<= diff;
+            }
+            self.bitmap |= 1;
+            self.last_seq = seq;
+            return true;
+        }
+        let diff = self.last_seq - seq;
+        if diff >= REPLAY_WINDOW_SIZE {
+            // Older than the window floor.
+            return false;
+        }
+        let mask = 1u64 << diff;
+        if self.bitmap & mask != 0 {
+            // Already seen.
+            return false;
+        }
+        self.bitmap |= mask;
+        true
+    }
+}
+
+pub(crate) struct SessionKey {
+    key: LessSafeKey,
+    /// Per-instance nonce prefix so that two `Crypto` objects created from
+    /// the same shared key never emit the same nonce.
+    nonce_salt: [u8; 4],
+    send_seq: u64,
+    replay: ReplayFilter,
+}
+
+/// The independent send/receive keys derived for one handshake epoch. Kept
+/// separate (unlike the static-key `SessionKey`) because the initiator's
+/// send key is the responder's receive key and vice versa.
+pub(crate) struct DirectionalKeys {
+    send: LessSafeKey,
+    recv: LessSafeKey,
+    send_seq: u64,
+    replay: ReplayFilter,
+}
+
+/// State for a `CryptoMethod::Noise` session: the pre-shared key mixed into
+/// every handshake, the ephemeral secret awaiting a peer response, and the
+/// transport keys derived from the most recent exchange.
+///
+/// Boxed in `Crypto::Noise` below because its handshake/rekey state makes it
+/// noticeably bigger than `SessionKey`; without that the `Crypto` enum's
+/// size would be dominated by this one rarely-used variant.
+pub(crate) struct NoiseSession {
+    psk: [u8; 32],
+    pending: Option<EphemeralSecret>,
+    keys: Option<DirectionalKeys>,
+    established_at: Option<Instant>,
+    messages_since_handshake: u64,
+}
+
+pub enum Crypto {
+    None,
+    ChaCha20Poly1305(SessionKey),
+    Aes256Gcm(SessionKey),
+    Noise(Box<NoiseSession>),
+}
+
+fn derive_key(method: CryptoMethod, password: &str) -> Vec<u8> {
+    debug_assert_ne!(method, CryptoMethod::Noise, "Noise sessions are created with Crypto::new_noise instead");
+    digest::digest(&digest::SHA256, password.as_bytes()).as_ref().to_vec()
+}
+
+fn write_nonce(nonce: &mut [u8], seq: u64, salt: [u8; 4]) {
+    nonce[0..8].copy_from_slice(&seq.to_be_bytes());
+    nonce[8..12].copy_from_slice(&salt);
+}
+
+fn read_seq(nonce: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&nonce[0..8]);
+    u64::from_be_bytes(bytes)
+}
+
+impl Crypto {
+    pub fn init() {
+        // AES-NI support detection is cached lazily by `aes256_available`,
+        // nothing else requires explicit setup.
+    }
+
+    pub fn aes256_available() -> bool {
+        #[cfg(target_arch = "x86_64")]
+        {
+            is_x86_feature_detected!("aes") && is_x86_feature_detected!("sse2")
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            false
+        }
+    }
+
+    pub fn from_shared_key(method: CryptoMethod, password: &str) -> Self {
+        let key_bytes = derive_key(method, password);
+        let algorithm = match method {
+            CryptoMethod::ChaCha20 => &aead::CHACHA20_POLY1305,
+            CryptoMethod::AES256 => &aead::AES_256_GCM,
+            CryptoMethod::Noise => panic!("Noise sessions are created with Crypto::new_noise instead"),
+        };
+        let unbound = UnboundKey::new(algorithm, &key_bytes).expect("invalid key length");
+        let mut nonce_salt = [0u8; 4];
+        SystemRandom::new().fill(&mut nonce_salt).expect("failed to generate nonce salt");
+        let session = SessionKey {
+            key: LessSafeKey::new(unbound),
+            nonce_salt,
+            send_seq: 0,
+            replay: ReplayFilter::new(),
+        };
+        match method {
+            CryptoMethod::ChaCha20 => Crypto::ChaCha20Poly1305(session),
+            CryptoMethod::AES256 => Crypto::Aes256Gcm(session),
+            CryptoMethod::Noise => unreachable!(),
+        }
+    }
+
+    /// Creates a `Crypto::Noise` session. `psk` is mixed into every
+    /// handshake alongside the X25519 shared secret so that an unauthenticated
+    /// ephemeral exchange alone — which an active man-in-the-middle could
+    /// complete with either end — is never enough to derive the transport
+    /// keys; both sides must also know `psk`.
+    pub fn new_noise(psk: &str) -> Self {
+        let psk = {
+            let d = digest::digest(&digest::SHA256, psk.as_bytes());
+            let mut out = [0u8; 32];
+            out.copy_from_slice(d.as_ref());
+            out
+        };
+        Crypto::Noise(Box::new(NoiseSession {
+            psk,
+            pending: None,
+            keys: None,
+            established_at: None,
+            messages_since_handshake: 0,
+        }))
+    }
+
+    /// Generates a fresh ephemeral keypair and returns the public half to
+    /// send as the handshake's stage-0 (initiator) or stage-1 (responder)
+    /// `Message::Init` payload. Calling this again later (once
+    /// `needs_rekey` is true) starts a rekey, discarding any secret left
+    /// over from a handshake that was never finished.
+    pub fn start_handshake(&mut self) -> [u8; HANDSHAKE_KEY_LEN] {
+        match *self {
+            Crypto::Noise(ref mut session) => {
+                let secret = EphemeralSecret::new(OsRng);
+                let public = PublicKey::from(&secret);
+                session.pending = Some(secret);
+                public.to_bytes()
+            }
+            _ => panic!("start_handshake called on a non-Noise Crypto"),
+        }
+    }
+
+    /// Combines the local ephemeral secret produced by `start_handshake`
+    /// with the peer's public key to derive new transport keys via HKDF,
+    /// replacing whatever keys were active before.
+    pub fn finish_handshake(&mut self, peer_public: &[u8; HANDSHAKE_KEY_LEN]) -> io::Result<()> {
+        match *self {
+            Crypto::Noise(ref mut session) => {
+                let secret = session.pending.take().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "finish_handshake without a pending start_handshake")
+                })?;
+                let local_public = PublicKey::from(&secret);
+                let remote_public = PublicKey::from(*peer_public);
+                let shared_secret = secret.diffie_hellman(&remote_public);
+
+                let mut ikm = shared_secret.as_bytes().to_vec();
+                ikm.extend_from_slice(&session.psk);
+                let hkdf = Hkdf::<Sha256>::new(None, &ikm);
+                let mut okm = [0u8; 64];
+                hkdf.expand(b"vpncloud noise transport keys", &mut okm)
+                    .expect("64 is a valid SHA-256 HKDF output length");
+
+                // Both sides derive the same two 32-byte keys; whichever
+                // side has the lexicographically larger public key always
+                // sends with the first half and receives with the second,
+                // so the two ends agree on directions without extra state.
+                let (send_bytes, recv_bytes) = if local_public.as_bytes() > remote_public.as_bytes() {
+                    (&okm[0..32], &okm[32..64])
+                } else {
+                    (&okm[32..64], &okm[0..32])
+                };
+                let send = LessSafeKey::new(UnboundKey::new(&aead::CHACHA20_POLY1305, send_bytes).expect("key is 32 bytes"));
+                let recv = LessSafeKey::new(UnboundKey::new(&aead::CHACHA20_POLY1305, recv_bytes).expect("key is 32 bytes"));
+
+                session.keys = Some(DirectionalKeys { send, recv, send_seq: 0, replay: ReplayFilter::new() });
+                session.established_at = Some(Instant::now());
+                session.messages_since_handshake = 0;
+                Ok(())
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "finish_handshake called on a non-Noise Crypto")),
+        }
+    }
+
+    /// Whether enough messages or time have passed on the current Noise
+    /// session that the caller should run `start_handshake` again to
+    /// rekey.
+    pub fn needs_rekey(&self) -> bool {
+        match *self {
+            Crypto::Noise(ref session) => {
+                let age = session.established_at.map_or(Duration::MAX, |t| t.elapsed());
+                session.messages_since_handshake >= REKEY_AFTER_MESSAGES || age >= REKEY_AFTER_TIME
+            }
+            _ => false,
+        }
+    }
+
+    pub fn additional_bytes(&self) -> usize {
+        match *self {
+            Crypto::None => 0,
+            Crypto::ChaCha20Poly1305(_) | Crypto::Aes256Gcm(_) | Crypto::Noise(_) => TAG_LEN,
+        }
+    }
+
+    /// Encrypts `buf[..len]` in place, appends the authentication tag right
+    /// after the plaintext and writes the nonce used for this packet into
+    /// `nonce` so the caller can put it on the wire. Returns the total
+    /// number of bytes now occupied in `buf` (plaintext length + tag).
+    pub fn encrypt(&mut self, buf: &mut [u8], len: usize, nonce: &mut [u8], header: &[u8]) -> io::Result<usize> {
+        match *self {
+            Crypto::None => Ok(len),
+            Crypto::ChaCha20Poly1305(ref mut session) | Crypto::Aes256Gcm(ref mut session) => {
+                session.send_seq += 1;
+                write_nonce(nonce, session.send_seq, session.nonce_salt);
+                let ring_nonce = Nonce::try_assume_unique_for_key(&nonce[..NONCE_LEN]).expect("invalid nonce length");
+                let tag = session.key
+                    .seal_in_place_separate_tag(ring_nonce, Aad::from(header), &mut buf[..len])
+                    .expect("encryption failed");
+                buf[len..len + TAG_LEN].copy_from_slice(tag.as_ref());
+                Ok(len + TAG_LEN)
+            }
+            Crypto::Noise(ref mut session) => {
+                let keys = session.keys.as_mut()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "encrypt called before the Noise handshake completed"))?;
+                keys.send_seq += 1;
+                write_nonce(nonce, keys.send_seq, [0u8; 4]);
+                let ring_nonce = Nonce::try_assume_unique_for_key(&nonce[..NONCE_LEN]).expect("invalid nonce length");
+                let tag = keys.send
+                    .seal_in_place_separate_tag(ring_nonce, Aad::from(header), &mut buf[..len])
+                    .expect("encryption failed");
+                buf[len..len + TAG_LEN].copy_from_slice(tag.as_ref());
+                session.messages_since_handshake += 1;
+                Ok(len + TAG_LEN)
+            }
+        }
+    }
+
+    /// Decrypts `buf` in place using `nonce` and `header` as associated
+    /// data. Authentication is checked first; only a packet whose tag is
+    /// valid is then run through the per-peer `ReplayFilter`, so replayed
+    /// copies of a previously accepted packet are rejected here.
+    pub fn decrypt(&mut self, buf: &mut [u8], nonce: &[u8], header: &[u8]) -> io::Result<usize> {
+        match *self {
+            Crypto::None => Ok(buf.len()),
+            Crypto::ChaCha20Poly1305(ref mut session) | Crypto::Aes256Gcm(ref mut session) => {
+                let ring_nonce = Nonce::try_assume_unique_for_key(&nonce[..NONCE_LEN])
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid nonce length"))?;
+                let plain = session.key
+                    .open_in_place(ring_nonce, Aad::from(header), buf)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "decryption failed"))?;
+                let seq = read_seq(nonce);
+                if !session.replay.check_and_update(seq) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "replayed packet rejected"));
+                }
+                Ok(plain.len())
+            }
+            Crypto::Noise(ref mut session) => {
+                let keys = session.keys.as_mut()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "decrypt called before the Noise handshake completed"))?;
+                let ring_nonce = Nonce::try_assume_unique_for_key(&nonce[..NONCE_LEN])
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid nonce length"))?;
+                let plain = keys.recv
+                    .open_in_place(ring_nonce, Aad::from(header), buf)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "decryption failed"))?;
+                let seq = read_seq(nonce);
+                if !keys.replay.check_and_update(seq) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "replayed packet rejected"));
+                }
+                Ok(plain.len())
+            }
+        }
+    }
+}
+
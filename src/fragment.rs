@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::udpmessage::Message;
+
+/// How long an incomplete reassembly buffer is kept around before it is
+/// dropped as undeliverable.
+pub const DEFAULT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Upper bound on the number of (peer, id) reassembly buffers held at
+/// once, so a flood of bogus fragment ids can't grow this without limit.
+pub const DEFAULT_MAX_BUFFERS: usize = 64;
+
+/// Upper bound on the `count` a fragment is allowed to claim for its
+/// datagram. A full-size jumbo Ethernet frame (9000 bytes) split at the
+/// smallest sane path MTU (68 bytes, the IPv4 minimum-MTU floor) needs
+/// well under 256 pieces, so a `count` above that is never legitimate and
+/// is rejected before it can force a correspondingly large allocation.
+const MAX_FRAGMENT_COUNT: u16 = 256;
+
+/// Splits outgoing `Data` payloads that exceed `mtu` into a series of
+/// `Message::DataFragment`s sharing a single 16-bit datagram id, handing
+/// out a fresh id for every payload that needs splitting.
+pub struct Fragmenter {
+    next_id: u16,
+}
+
+impl Fragmenter {
+    pub fn new() -> Self {
+        Fragmenter { next_id: 0 }
+    }
+
+    /// Splits `data` into `Message::DataFragment`s of at most `mtu` bytes
+    /// each. Callers should only reach for this once `data.len() > mtu`;
+    /// called on anything that already fits, it still returns a (single
+    /// element) series rather than a plain `Message::Data`.
+    pub fn fragment<'a>(&mut self, data: &'a [u8], mtu: usize) -> Vec<Message<'a>> {
+        assert!(mtu > 0, "mtu must be positive");
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let count = ((data.len() + mtu - 1) / mtu).max(1) as u16;
+        if data.is_empty() {
+            return vec![Message::DataFragment { id, index: 0, count, bytes: data }];
+        }
+        data.chunks(mtu)
+            .enumerate()
+            .map(|(index, bytes)| Message::DataFragment { id, index: index as u16, count, bytes })
+            .collect()
+    }
+}
+
+struct Buffer {
+    pieces: Vec<Option<Vec<u8>>>,
+    received: usize,
+    created_at: Instant,
+}
+
+/// Receive-side counterpart to `Fragmenter`: accumulates `DataFragment`s
+/// per (peer, id) and hands back the reassembled payload once every piece
+/// has arrived. Buffers that never complete are expired after `timeout`,
+/// and at most `max_buffers` partial datagrams are tracked at a time so a
+/// peer can't exhaust memory by opening many ids that are never finished.
+pub struct Reassembler {
+    buffers: HashMap<(SocketAddr, u16), Buffer>,
+    max_buffers: usize,
+    timeout: Duration,
+}
+
+impl Reassembler {
+    pub fn new(max_buffers: usize, timeout: Duration) -> Self {
+        Reassembler { buffers: HashMap::new(), max_buffers, timeout }
+    }
+
+    /// Feeds one fragment in. Returns the reassembled payload once `count`
+    /// distinct pieces for `(peer, id)` have been seen. Duplicate or
+    /// overlapping fragments (an `index` already filled) are ignored; the
+    /// first copy received wins.
+    pub fn insert(&mut self, peer: SocketAddr, id: u16, index: u16, count: u16, bytes: &[u8]) -> Option<Vec<u8>> {
+        if count == 0 || count > MAX_FRAGMENT_COUNT || index >= count {
+            return None;
+        }
+        self.expire_stale();
+        let key = (peer, id);
+        if !self.buffers.contains_key(&key) {
+            if self.buffers.len() >= self.max_buffers {
+                return None;
+            }
+            self.buffers.insert(
+                key,
+                Buffer { pieces: vec![None; count as usize], received: 0, created_at: Instant::now() },
+            );
+        }
+        let buffer = self.buffers.get_mut(&key).unwrap();
+        let index = index as usize;
+        if index >= buffer.pieces.len() {
+            return None;
+        }
+        if buffer.pieces[index].is_none() {
+            buffer.pieces[index] = Some(bytes.to_vec());
+            buffer.received += 1;
+        }
+        if buffer.received < buffer.pieces.len() {
+            return None;
+        }
+        let buffer = self.buffers.remove(&key).unwrap();
+        let mut data = Vec::new();
+        for piece in buffer.pieces {
+            data.extend_from_slice(&piece.expect("all pieces present once received == pieces.len()"));
+        }
+        Some(data)
+    }
+
+    fn expire_stale(&mut self) {
+        let timeout = self.timeout;
+        self.buffers.retain(|_, buffer| buffer.created_at.elapsed() < timeout);
+    }
+}
@@ -0,0 +1,118 @@
+use std::io;
+use std::net::SocketAddr;
+
+use super::types::{Address, Table};
+
+/// Parses the source/destination addresses out of a raw IPv4 or IPv6
+/// packet, ignoring everything else (options, payload, checksums).
+pub struct Packet;
+
+impl Packet {
+    pub fn parse(data: &[u8]) -> io::Result<(Address, Address)> {
+        if data.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "packet is empty"));
+        }
+        match data[0] >> 4 {
+            4 => {
+                if data.len() < 20 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated ipv4 packet"));
+                }
+                let mut src = [0u8; 16];
+                src[0..4].copy_from_slice(&data[12..16]);
+                let mut dst = [0u8; 16];
+                dst[0..4].copy_from_slice(&data[16..20]);
+                Ok((Address { data: src, len: 4 }, Address { data: dst, len: 4 }))
+            }
+            6 => {
+                if data.len() < 40 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated ipv6 packet"));
+                }
+                let mut src = [0u8; 16];
+                src.copy_from_slice(&data[8..24]);
+                let mut dst = [0u8; 16];
+                dst.copy_from_slice(&data[24..40]);
+                Ok((Address { data: src, len: 16 }, Address { data: dst, len: 16 }))
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unknown ip version")),
+        }
+    }
+}
+
+fn bit_at(data: &[u8; 16], index: usize) -> usize {
+    let byte = data[index / 8];
+    let shift = 7 - (index % 8);
+    ((byte >> shift) & 1) as usize
+}
+
+/// One node of the binary trie (one level per address bit, no path
+/// compression) backing `RoutingTable`: `peer` is
+/// set when some learned prefix ends exactly here, and `children[bit]` is
+/// the subtree for addresses whose next bit is `bit`.
+struct Node {
+    peer: Option<SocketAddr>,
+    children: [Option<Box<Node>>; 2],
+}
+
+impl Node {
+    fn new() -> Self {
+        Node { peer: None, children: [None, None] }
+    }
+}
+
+/// A longest-prefix-match routing table, keyed on the raw bits of an
+/// `Address` (4 bytes for IPv4, 16 for IPv6). `learn` walks `prefix_len`
+/// bits from the most significant end, creating nodes as needed, and
+/// stores the peer at the node the walk ends on. `lookup` descends the
+/// same way for the full address, remembering the deepest node seen so
+/// far that carries a peer, so overlapping routes (e.g. a /16 covering a
+/// more specific /27) resolve to the most specific one and a 0-bit prefix
+/// acts as a default route. This makes both operations O(prefix length),
+/// independent of how many routes are stored.
+pub struct RoutingTable {
+    root: Node,
+}
+
+impl RoutingTable {
+    pub fn new() -> Self {
+        RoutingTable { root: Node::new() }
+    }
+
+    pub fn learn(&mut self, addr: Address, prefix_len: Option<u8>, peer: SocketAddr) {
+        let bits = prefix_len.map(|len| len as usize).unwrap_or(addr.len as usize * 8);
+        let mut node = &mut self.root;
+        for i in 0..bits {
+            let bit = bit_at(&addr.data, i);
+            node = node.children[bit].get_or_insert_with(|| Box::new(Node::new()));
+        }
+        node.peer = Some(peer);
+    }
+
+    pub fn lookup(&mut self, addr: &Address) -> Option<SocketAddr> {
+        let bits = addr.len as usize * 8;
+        let mut node = &self.root;
+        let mut result = node.peer;
+        for i in 0..bits {
+            let bit = bit_at(&addr.data, i);
+            match node.children[bit] {
+                Some(ref child) => {
+                    node = child;
+                    if node.peer.is_some() {
+                        result = node.peer;
+                    }
+                }
+                None => break,
+            }
+        }
+        result
+    }
+}
+
+impl Table for RoutingTable {
+    fn learn(&mut self, addr: Address, prefix_len: Option<u8>, peer: SocketAddr) {
+        self.learn(addr, prefix_len, peer)
+    }
+
+    fn lookup(&mut self, addr: &Address) -> Option<SocketAddr> {
+        self.lookup(addr)
+    }
+}
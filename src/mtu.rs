@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Binary-searches the path MTU to one peer by probing with `Message::Ping`
+/// of increasing or decreasing size and observing whether a `Message::Pong`
+/// of the same size comes back. `floor` is a size assumed to always work
+/// (e.g. the IPv4 minimum reassembly size, 576) and `ceiling` starts out one
+/// past the local interface MTU; each probe halves the gap until it closes,
+/// at which point `floor` is the largest size the path actually delivers.
+pub struct MtuDiscovery {
+    floor: u16,
+    ceiling: u16,
+    probe: u16,
+}
+
+impl MtuDiscovery {
+    pub fn new(floor: u16, interface_mtu: u16) -> Self {
+        let mut discovery = MtuDiscovery { floor, ceiling: interface_mtu + 1, probe: 0 };
+        discovery.probe = discovery.next_probe();
+        discovery
+    }
+
+    fn next_probe(&self) -> u16 {
+        self.floor + (self.ceiling - self.floor) / 2
+    }
+
+    /// Size of the next `Ping` to send.
+    pub fn probe_size(&self) -> u16 {
+        self.probe
+    }
+
+    /// Call once a `Pong` of `probe_size()` arrives back.
+    pub fn on_success(&mut self) {
+        self.floor = self.probe;
+        if !self.is_converged() {
+            self.probe = self.next_probe();
+        }
+    }
+
+    /// Call once the `Ping` at `probe_size()` goes unanswered.
+    pub fn on_failure(&mut self) {
+        self.ceiling = self.probe;
+        if !self.is_converged() {
+            self.probe = self.next_probe();
+        }
+    }
+
+    /// True once no integer size remains between the last confirmed
+    /// success and the last confirmed failure.
+    pub fn is_converged(&self) -> bool {
+        self.ceiling - self.floor <= 1
+    }
+
+    /// The largest size confirmed to work so far.
+    pub fn confirmed_mtu(&self) -> u16 {
+        self.floor
+    }
+}
+
+/// Tracks one `MtuDiscovery` per peer and exposes the confirmed size as the
+/// MTU that `fragment::Fragmenter` should split outgoing payloads at,
+/// falling back to `floor` for peers that haven't been probed yet.
+pub struct PeerMtuTable {
+    floor: u16,
+    interface_mtu: u16,
+    discoveries: HashMap<SocketAddr, MtuDiscovery>,
+}
+
+impl PeerMtuTable {
+    pub fn new(floor: u16, interface_mtu: u16) -> Self {
+        PeerMtuTable { floor, interface_mtu, discoveries: HashMap::new() }
+    }
+
+    fn discovery(&mut self, peer: SocketAddr) -> &mut MtuDiscovery {
+        let floor = self.floor;
+        let interface_mtu = self.interface_mtu;
+        self.discoveries.entry(peer).or_insert_with(|| MtuDiscovery::new(floor, interface_mtu))
+    }
+
+    pub fn probe_size(&mut self, peer: SocketAddr) -> u16 {
+        self.discovery(peer).probe_size()
+    }
+
+    pub fn on_success(&mut self, peer: SocketAddr) {
+        self.discovery(peer).on_success();
+    }
+
+    pub fn on_failure(&mut self, peer: SocketAddr) {
+        self.discovery(peer).on_failure();
+    }
+
+    /// The MTU to pass to `Fragmenter::fragment` for `peer`.
+    pub fn fragmentation_mtu(&self, peer: SocketAddr) -> u16 {
+        self.discoveries.get(&peer).map(MtuDiscovery::confirmed_mtu).unwrap_or(self.floor)
+    }
+}
@@ -1,11 +1,14 @@
 use std::net::{ToSocketAddrs, SocketAddr};
 use std::str::FromStr;
+use std::time::Duration;
 
 use super::ethernet::{Frame, SwitchTable};
 use super::ip::{RoutingTable, Packet};
 use super::types::{Protocol, Address, Range, Table};
 use super::udpmessage::{Options, Message, decode, encode};
 use super::crypto::{Crypto, CryptoMethod};
+use super::fragment::{Fragmenter, Reassembler};
+use super::mtu::MtuDiscovery;
 
 
 #[test]
@@ -15,7 +18,7 @@ fn udpmessage_packet() {
     let payload = [1,2,3,4,5];
     let msg = Message::Data(&payload);
     let mut buf = [0; 1024];
-    let size = encode(&mut options, &msg, &mut buf[..], &mut crypto);
+    let size = encode(&mut options, &msg, &mut buf[..], &mut crypto).unwrap();
     assert_eq!(size, 13);
     assert_eq!(&buf[..8], &[118,112,110,1,0,0,0,0]);
     let (options2, msg2) = decode(&mut buf[..size], &mut crypto).unwrap();
@@ -30,7 +33,7 @@ fn udpmessage_encrypted() {
     let payload = [1,2,3,4,5];
     let msg = Message::Data(&payload);
     let mut buf = [0; 1024];
-    let size = encode(&mut options, &msg, &mut buf[..], &mut crypto);
+    let size = encode(&mut options, &msg, &mut buf[..], &mut crypto).unwrap();
     assert_eq!(size, 41);
     assert_eq!(&buf[..8], &[118,112,110,1,1,0,0,0]);
     let (options2, msg2) = decode(&mut buf[..size], &mut crypto).unwrap();
@@ -45,7 +48,7 @@ fn udpmessage_peers() {
     let mut crypto = Crypto::None;
     let msg = Message::Peers(vec![SocketAddr::from_str("1.2.3.4:123").unwrap(), SocketAddr::from_str("5.6.7.8:12345").unwrap(), SocketAddr::from_str("[0001:0203:0405:0607:0809:0a0b:0c0d:0e0f]:6789").unwrap()]);
     let mut buf = [0; 1024];
-    let size = encode(&mut options, &msg, &mut buf[..], &mut crypto);
+    let size = encode(&mut options, &msg, &mut buf[..], &mut crypto).unwrap();
     assert_eq!(size, 40);
     let should = [118,112,110,1,0,0,0,1,2,1,2,3,4,0,123,5,6,7,8,48,57,1,0,1,2,3,4,5,6,7,
         8,9,10,11,12,13,14,15,26,133];
@@ -64,7 +67,7 @@ fn udpmessage_option_network_id() {
     let mut crypto = Crypto::None;
     let msg = Message::Close;
     let mut buf = [0; 1024];
-    let size = encode(&mut options, &msg, &mut buf[..], &mut crypto);
+    let size = encode(&mut options, &msg, &mut buf[..], &mut crypto).unwrap();
     assert_eq!(size, 16);
     assert_eq!(&buf[..size], &[118,112,110,1,0,0,1,3,0,0,0,0,0,0,0,134]);
     let (options2, msg2) = decode(&mut buf[..size], &mut crypto).unwrap();
@@ -82,7 +85,7 @@ fn udpmessage_init() {
     let node_id = [0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15];
     let msg = Message::Init(0, node_id, addrs);
     let mut buf = [0; 1024];
-    let size = encode(&mut options, &msg, &mut buf[..], &mut crypto);
+    let size = encode(&mut options, &msg, &mut buf[..], &mut crypto).unwrap();
     assert_eq!(size, 40);
     let should = [118,112,110,1,0,0,0,2,0,0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,2,4,0,1,2,3,24,6,0,1,2,3,4,5,16];
     for i in 0..size {
@@ -99,7 +102,7 @@ fn udpmessage_close() {
     let mut crypto = Crypto::None;
     let msg = Message::Close;
     let mut buf = [0; 1024];
-    let size = encode(&mut options, &msg, &mut buf[..], &mut crypto);
+    let size = encode(&mut options, &msg, &mut buf[..], &mut crypto).unwrap();
     assert_eq!(size, 8);
     assert_eq!(&buf[..size], &[118,112,110,1,0,0,0,3]);
     let (options2, msg2) = decode(&mut buf[..size], &mut crypto).unwrap();
@@ -132,6 +135,73 @@ fn udpmessage_invalid_crypto() {
     assert!(decode(&mut [0x76,0x70,0x6e,1,1,0,0,0], &mut crypto).is_err());
 }
 
+#[test]
+fn udpmessage_data_fragment_roundtrip() {
+    let payload: Vec<u8> = (0..3000).map(|i| (i % 256) as u8).collect();
+    let mtu = 1400;
+    let mut fragmenter = Fragmenter::new();
+    let fragments = fragmenter.fragment(&payload, mtu);
+    assert_eq!(fragments.len(), 3);
+
+    let mut options = Options::default();
+    let mut crypto = Crypto::None;
+    let mut reassembler = Reassembler::new(16, Duration::from_secs(30));
+    let peer: SocketAddr = "1.2.3.4:5678".to_socket_addrs().unwrap().next().unwrap();
+
+    let mut reassembled = None;
+    for fragment in &fragments {
+        let mut buf = [0u8; 2048];
+        let size = encode(&mut options, fragment, &mut buf, &mut crypto).unwrap();
+        let (_, decoded) = decode(&mut buf[..size], &mut crypto).unwrap();
+        match decoded {
+            Message::DataFragment { id, index, count, bytes } => {
+                reassembled = reassembler.insert(peer, id, index, count, bytes);
+            }
+            _ => panic!("expected a DataFragment message"),
+        }
+    }
+    assert_eq!(reassembled, Some(payload));
+}
+
+#[test]
+fn fragment_reassembler_rejects_implausible_fragment_count() {
+    let mut reassembler = Reassembler::new(16, Duration::from_secs(30));
+    let peer: SocketAddr = "1.2.3.4:5678".to_socket_addrs().unwrap().next().unwrap();
+    // A single small fragment claiming tens of thousands of pieces would
+    // force an outsized allocation before a second piece ever arrives.
+    assert_eq!(reassembler.insert(peer, 1, 0, 65535, b"x"), None);
+}
+
+#[test]
+fn udpmessage_ping_pong() {
+    let mut options = Options::default();
+    let mut crypto = Crypto::None;
+    let mut buf = [0u8; 1500];
+
+    let size = encode(&mut options, &Message::Ping(1400), &mut buf, &mut crypto).unwrap();
+    let (_, msg) = decode(&mut buf[..size], &mut crypto).unwrap();
+    assert_eq!(msg, Message::Ping(1400));
+
+    let size = encode(&mut options, &Message::Pong(1400), &mut buf, &mut crypto).unwrap();
+    let (_, msg) = decode(&mut buf[..size], &mut crypto).unwrap();
+    assert_eq!(msg, Message::Pong(1400));
+}
+
+#[test]
+fn mtu_discovery_converges() {
+    // Every probe larger than this is dropped by the simulated path.
+    let drop_threshold: u16 = 1372;
+    let mut discovery = MtuDiscovery::new(576, 1500);
+    while !discovery.is_converged() {
+        if discovery.probe_size() <= drop_threshold {
+            discovery.on_success();
+        } else {
+            discovery.on_failure();
+        }
+    }
+    assert_eq!(discovery.confirmed_mtu(), drop_threshold);
+}
+
 
 #[test]
 fn decode_frame_without_vlan() {
@@ -257,7 +327,7 @@ fn message_fmt() {
 #[test]
 fn encrypt_decrypt_chacha20poly1305() {
     let mut sender = Crypto::from_shared_key(CryptoMethod::ChaCha20, "test");
-    let receiver = Crypto::from_shared_key(CryptoMethod::ChaCha20, "test");
+    let mut receiver = Crypto::from_shared_key(CryptoMethod::ChaCha20, "test");
     let msg = "HelloWorld0123456789";
     let msg_bytes = msg.as_bytes();
     let mut buffer = [0u8; 1024];
@@ -266,18 +336,119 @@ fn encrypt_decrypt_chacha20poly1305() {
         buffer[i] = msg_bytes[i];
     }
     let mut nonce1 = [0u8; 12];
-    let size = sender.encrypt(&mut buffer, msg_bytes.len(), &mut nonce1, &header);
+    let size = sender.encrypt(&mut buffer, msg_bytes.len(), &mut nonce1, &header).unwrap();
     assert_eq!(size, msg_bytes.len() + sender.additional_bytes());
     assert!(msg_bytes != &buffer[..msg_bytes.len()] as &[u8]);
     receiver.decrypt(&mut buffer[..size], &nonce1, &header).unwrap();
     assert_eq!(msg_bytes, &buffer[..msg_bytes.len()] as &[u8]);
     let mut nonce2 = [0u8; 12];
-    let size = sender.encrypt(&mut buffer, msg_bytes.len(), &mut nonce2, &header);
+    let size = sender.encrypt(&mut buffer, msg_bytes.len(), &mut nonce2, &header).unwrap();
     assert!(nonce1 != nonce2);
     receiver.decrypt(&mut buffer[..size], &nonce2, &header).unwrap();
     assert_eq!(msg_bytes, &buffer[..msg_bytes.len()] as &[u8]);
 }
 
+#[test]
+fn noise_handshake_produces_matching_transport_keys() {
+    let mut initiator = Crypto::new_noise("test psk");
+    let mut responder = Crypto::new_noise("test psk");
+
+    let initiator_public = initiator.start_handshake();
+    let responder_public = responder.start_handshake();
+    initiator.finish_handshake(&responder_public).unwrap();
+    responder.finish_handshake(&initiator_public).unwrap();
+
+    let msg_bytes = b"HelloWorld0123456789";
+    let mut buffer = [0u8; 1024];
+    let header = [0u8; 8];
+    buffer[..msg_bytes.len()].copy_from_slice(msg_bytes);
+    let mut nonce = [0u8; 12];
+    let size = initiator.encrypt(&mut buffer, msg_bytes.len(), &mut nonce, &header).unwrap();
+    responder.decrypt(&mut buffer[..size], &nonce, &header).unwrap();
+    assert_eq!(msg_bytes, &buffer[..msg_bytes.len()] as &[u8]);
+}
+
+#[test]
+fn noise_rekey_invalidates_previous_key() {
+    let mut initiator = Crypto::new_noise("test psk");
+    let mut responder = Crypto::new_noise("test psk");
+    let initiator_public = initiator.start_handshake();
+    let responder_public = responder.start_handshake();
+    initiator.finish_handshake(&responder_public).unwrap();
+    responder.finish_handshake(&initiator_public).unwrap();
+
+    let msg_bytes = b"HelloWorld0123456789";
+    let mut buffer = [0u8; 1024];
+    let header = [0u8; 8];
+    buffer[..msg_bytes.len()].copy_from_slice(msg_bytes);
+    let mut nonce = [0u8; 12];
+    let size = initiator.encrypt(&mut buffer, msg_bytes.len(), &mut nonce, &header).unwrap();
+    let old_ciphertext = buffer;
+
+    let initiator_public = initiator.start_handshake();
+    let responder_public = responder.start_handshake();
+    initiator.finish_handshake(&responder_public).unwrap();
+    responder.finish_handshake(&initiator_public).unwrap();
+
+    let mut replayed = old_ciphertext;
+    assert!(responder.decrypt(&mut replayed[..size], &nonce, &header).is_err());
+}
+
+#[test]
+fn noise_handshake_init_roundtrip_through_encode_decode() {
+    use super::udpmessage::{decode_handshake_init, encode_handshake_init};
+
+    let mut initiator = Crypto::new_noise("test psk");
+    let mut responder = Crypto::new_noise("test psk");
+    let mut options = Options::default();
+    let mut crypto = Crypto::None;
+    let mut buf = [0u8; 1024];
+
+    let initiator_public = initiator.start_handshake();
+    let stage0 = encode_handshake_init(0, &initiator_public);
+    let size = encode(&mut options, &stage0, &mut buf, &mut crypto).unwrap();
+    let (_, decoded) = decode(&mut buf[..size], &mut crypto).unwrap();
+    let received_initiator_public = decode_handshake_init(&decoded).unwrap();
+    assert_eq!(received_initiator_public, initiator_public);
+
+    let responder_public = responder.start_handshake();
+    let stage1 = encode_handshake_init(1, &responder_public);
+    let size = encode(&mut options, &stage1, &mut buf, &mut crypto).unwrap();
+    let (_, decoded) = decode(&mut buf[..size], &mut crypto).unwrap();
+    let received_responder_public = decode_handshake_init(&decoded).unwrap();
+    assert_eq!(received_responder_public, responder_public);
+
+    initiator.finish_handshake(&received_responder_public).unwrap();
+    responder.finish_handshake(&received_initiator_public).unwrap();
+
+    let msg_bytes = b"HelloWorld0123456789";
+    let mut buffer = [0u8; 1024];
+    let header = [0u8; 8];
+    buffer[..msg_bytes.len()].copy_from_slice(msg_bytes);
+    let mut nonce = [0u8; 12];
+    let size = initiator.encrypt(&mut buffer, msg_bytes.len(), &mut nonce, &header).unwrap();
+    responder.decrypt(&mut buffer[..size], &nonce, &header).unwrap();
+    assert_eq!(msg_bytes, &buffer[..msg_bytes.len()] as &[u8]);
+}
+
+#[test]
+fn replay_protection_rejects_replayed_packet() {
+    let mut sender = Crypto::from_shared_key(CryptoMethod::ChaCha20, "test");
+    let mut receiver = Crypto::from_shared_key(CryptoMethod::ChaCha20, "test");
+    let msg = "HelloWorld0123456789";
+    let msg_bytes = msg.as_bytes();
+    let mut buffer = [0u8; 1024];
+    let header = [0u8; 8];
+    for i in 0..msg_bytes.len() {
+        buffer[i] = msg_bytes[i];
+    }
+    let mut nonce = [0u8; 12];
+    let size = sender.encrypt(&mut buffer, msg_bytes.len(), &mut nonce, &header).unwrap();
+    let mut replayed = buffer;
+    receiver.decrypt(&mut buffer[..size], &nonce, &header).unwrap();
+    assert!(receiver.decrypt(&mut replayed[..size], &nonce, &header).is_err());
+}
+
 #[test]
 fn encrypt_decrypt_aes256() {
     Crypto::init();
@@ -285,7 +456,7 @@ fn encrypt_decrypt_aes256() {
         return
     }
     let mut sender = Crypto::from_shared_key(CryptoMethod::AES256, "test");
-    let receiver = Crypto::from_shared_key(CryptoMethod::AES256, "test");
+    let mut receiver = Crypto::from_shared_key(CryptoMethod::AES256, "test");
     let msg = "HelloWorld0123456789";
     let msg_bytes = msg.as_bytes();
     let mut buffer = [0u8; 1024];
@@ -294,13 +465,13 @@ fn encrypt_decrypt_aes256() {
         buffer[i] = msg_bytes[i];
     }
     let mut nonce1 = [0u8; 12];
-    let size = sender.encrypt(&mut buffer, msg_bytes.len(), &mut nonce1, &header);
+    let size = sender.encrypt(&mut buffer, msg_bytes.len(), &mut nonce1, &header).unwrap();
     assert_eq!(size, msg_bytes.len() + sender.additional_bytes());
     assert!(msg_bytes != &buffer[..msg_bytes.len()] as &[u8]);
     receiver.decrypt(&mut buffer[..size], &nonce1, &header).unwrap();
     assert_eq!(msg_bytes, &buffer[..msg_bytes.len()] as &[u8]);
     let mut nonce2 = [0u8; 12];
-    let size = sender.encrypt(&mut buffer, msg_bytes.len(), &mut nonce2, &header);
+    let size = sender.encrypt(&mut buffer, msg_bytes.len(), &mut nonce2, &header).unwrap();
     assert!(nonce1 != nonce2);
     receiver.decrypt(&mut buffer[..size], &nonce2, &header).unwrap();
     assert_eq!(msg_bytes, &buffer[..msg_bytes.len()] as &[u8]);
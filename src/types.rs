@@ -0,0 +1,98 @@
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+/// The kind of payload carried over the tunnel: raw ethernet frames (tap
+/// mode) or IP packets (tun mode).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Ethernet,
+    IP,
+}
+
+/// A hardware or network address. Used both for switch/routing table keys
+/// and for range prefixes announced in `Message::Init`. `data` always holds
+/// 16 bytes so the same type covers MAC (6), vlan-tagged MAC (8) and IPv4
+/// (4) / IPv6 (16) addresses; `len` says how many of those bytes are valid.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Address {
+    pub data: [u8; 16],
+    pub len: u8,
+}
+
+impl FromStr for Address {
+    type Err = io::Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = Ipv4Addr::from_str(text) {
+            let mut data = [0u8; 16];
+            data[0..4].copy_from_slice(&addr.octets());
+            return Ok(Address { data, len: 4 });
+        }
+        if let Ok(addr) = Ipv6Addr::from_str(text) {
+            let mut data = [0u8; 16];
+            data.copy_from_slice(&addr.octets());
+            return Ok(Address { data, len: 16 });
+        }
+        let groups: Vec<&str> = text.split(':').collect();
+        if groups.len() == 6 {
+            let mut data = [0u8; 16];
+            for (i, group) in groups.iter().enumerate() {
+                data[i] = u8::from_str_radix(group, 16)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Failed to parse address {}", text)))?;
+            }
+            return Ok(Address { data, len: 6 });
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Failed to parse address {}", text)))
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self.len {
+            4 => write!(formatter, "{}.{}.{}.{}", self.data[0], self.data[1], self.data[2], self.data[3]),
+            6 => write!(formatter, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+                self.data[0], self.data[1], self.data[2], self.data[3], self.data[4], self.data[5]),
+            8 => write!(formatter, "vlan{}/{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+                ((self.data[0] as u16) << 8) | self.data[1] as u16,
+                self.data[2], self.data[3], self.data[4], self.data[5], self.data[6], self.data[7]),
+            16 => {
+                for i in 0..8 {
+                    if i > 0 {
+                        write!(formatter, ":")?;
+                    }
+                    write!(formatter, "{:02x}{:02x}", self.data[2 * i], self.data[2 * i + 1])?;
+                }
+                Ok(())
+            }
+            _ => write!(formatter, "<invalid address>"),
+        }
+    }
+}
+
+/// A network announced in `Message::Init`: all addresses that share the
+/// first `prefix_len` bits of `base`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Range {
+    pub base: Address,
+    pub prefix_len: u8,
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}/{}", self.base, self.prefix_len)
+    }
+}
+
+/// 16 bytes that uniquely identify a node across restarts, exchanged as
+/// part of `udpmessage::Message::Init`.
+pub type NodeId = [u8; 16];
+
+/// Common interface shared by `ethernet::SwitchTable` and
+/// `ip::RoutingTable`: learn which peer a (possibly prefixed) address is
+/// reachable through, and look the peer for an address back up.
+pub trait Table {
+    fn learn(&mut self, addr: Address, prefix_len: Option<u8>, peer: SocketAddr);
+    fn lookup(&mut self, addr: &Address) -> Option<SocketAddr>;
+}
@@ -0,0 +1,389 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use super::crypto::{Crypto, HANDSHAKE_KEY_LEN, NONCE_LEN};
+use super::types::{Address, NodeId, Range};
+
+const MAGIC: [u8; 3] = *b"vpn";
+const VERSION: u8 = 1;
+const FIXED_HEADER_LEN: usize = 8;
+
+const FLAG_NETWORK_ID: u8 = 0x01;
+
+const MSG_TYPE_DATA: u8 = 0;
+const MSG_TYPE_PEERS: u8 = 1;
+const MSG_TYPE_INIT: u8 = 2;
+const MSG_TYPE_CLOSE: u8 = 3;
+const MSG_TYPE_DATA_FRAGMENT: u8 = 4;
+const MSG_TYPE_PING: u8 = 5;
+const MSG_TYPE_PONG: u8 = 6;
+const MSG_TYPE_NOISE_INIT: u8 = 7;
+
+/// Per-datagram options, flagged in the fixed header and carried right after
+/// it, independent of the message payload that follows them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    pub network_id: Option<u16>,
+}
+
+#[derive(PartialEq)]
+pub enum Message<'a> {
+    Data(&'a [u8]),
+    Peers(Vec<SocketAddr>),
+    Init(u8, NodeId, Vec<Range>),
+    Close,
+    /// One piece of a `Data` payload that was too big for the path MTU.
+    /// `index` and `count` locate it within the `id`-numbered datagram it
+    /// belongs to; the receiver reassembles once all `count` pieces with
+    /// that `id` have arrived (see `fragment::Reassembler`).
+    DataFragment { id: u16, index: u16, count: u16, bytes: &'a [u8] },
+    /// A path-MTU probe / liveness keepalive padded to the given size in
+    /// bytes. A `Pong` of the same size echoed back confirms the path
+    /// delivers datagrams that large (see `mtu::MtuDiscovery`).
+    Ping(u16),
+    Pong(u16),
+    /// A `CryptoMethod::Noise` handshake exchange: `stage` 0 carries the
+    /// initiator's ephemeral X25519 public key, `stage` 1 the responder's.
+    /// Distinct from `Init` (which announces a peer's real `NodeId` and
+    /// owned `Range`s) so a receiver never has to guess which one a given
+    /// message is.
+    NoiseInit { stage: u8, public_key: [u8; HANDSHAKE_KEY_LEN] },
+}
+
+impl<'a> fmt::Debug for Message<'a> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Message::Data(data) => write!(formatter, "Data({} bytes)", data.len()),
+            Message::Peers(ref addrs) => {
+                write!(formatter, "Peers [")?;
+                for (i, addr) in addrs.iter().enumerate() {
+                    if i > 0 {
+                        write!(formatter, ", ")?;
+                    }
+                    write!(formatter, "{}", addr)?;
+                }
+                write!(formatter, "]")
+            }
+            Message::Init(stage, ref node_id, ref ranges) => {
+                write!(formatter, "Init(stage={}, node_id=", stage)?;
+                for b in node_id {
+                    write!(formatter, "{:02x}", b)?;
+                }
+                write!(formatter, ", [")?;
+                for (i, range) in ranges.iter().enumerate() {
+                    if i > 0 {
+                        write!(formatter, ", ")?;
+                    }
+                    write!(formatter, "{}", range)?;
+                }
+                write!(formatter, "])")
+            }
+            Message::Close => write!(formatter, "Close"),
+            Message::DataFragment { id, index, count, bytes } => {
+                write!(formatter, "DataFragment(id={}, {}/{}, {} bytes)", id, index + 1, count, bytes.len())
+            }
+            Message::Ping(size) => write!(formatter, "Ping({} bytes)", size),
+            Message::Pong(size) => write!(formatter, "Pong({} bytes)", size),
+            Message::NoiseInit { stage, ref public_key } => {
+                write!(formatter, "NoiseInit(stage={}, public_key=", stage)?;
+                for b in public_key {
+                    write!(formatter, "{:02x}", b)?;
+                }
+                write!(formatter, ")")
+            }
+        }
+    }
+}
+
+impl<'a> Message<'a> {
+    fn type_id(&self) -> u8 {
+        match *self {
+            Message::Data(_) => MSG_TYPE_DATA,
+            Message::Peers(_) => MSG_TYPE_PEERS,
+            Message::Init(..) => MSG_TYPE_INIT,
+            Message::Close => MSG_TYPE_CLOSE,
+            Message::DataFragment { .. } => MSG_TYPE_DATA_FRAGMENT,
+            Message::Ping(_) => MSG_TYPE_PING,
+            Message::Pong(_) => MSG_TYPE_PONG,
+            Message::NoiseInit { .. } => MSG_TYPE_NOISE_INIT,
+        }
+    }
+}
+
+fn crypto_method_byte(crypto: &Crypto) -> u8 {
+    match *crypto {
+        Crypto::None => 0,
+        Crypto::ChaCha20Poly1305(_) => 1,
+        Crypto::Aes256Gcm(_) => 2,
+        Crypto::Noise(_) => 3,
+    }
+}
+
+fn need(buf: &[u8], pos: usize, len: usize) -> io::Result<()> {
+    if buf.len() < pos + len {
+        Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated message"))
+    } else {
+        Ok(())
+    }
+}
+
+fn encode_payload(msg: &Message, buf: &mut [u8]) -> usize {
+    match *msg {
+        Message::Data(data) => {
+            buf[..data.len()].copy_from_slice(data);
+            data.len()
+        }
+        Message::Peers(ref addrs) => {
+            let mut pos = 0;
+            let v4: Vec<_> = addrs.iter().filter(|a| a.is_ipv4()).collect();
+            let v6: Vec<_> = addrs.iter().filter(|a| a.is_ipv6()).collect();
+            buf[pos] = v4.len() as u8;
+            pos += 1;
+            for addr in &v4 {
+                if let SocketAddr::V4(a) = addr {
+                    buf[pos..pos + 4].copy_from_slice(&a.ip().octets());
+                    pos += 4;
+                    buf[pos..pos + 2].copy_from_slice(&a.port().to_be_bytes());
+                    pos += 2;
+                }
+            }
+            buf[pos] = v6.len() as u8;
+            pos += 1;
+            for addr in &v6 {
+                if let SocketAddr::V6(a) = addr {
+                    buf[pos..pos + 16].copy_from_slice(&a.ip().octets());
+                    pos += 16;
+                    buf[pos..pos + 2].copy_from_slice(&a.port().to_be_bytes());
+                    pos += 2;
+                }
+            }
+            pos
+        }
+        Message::Init(stage, ref node_id, ref ranges) => {
+            let mut pos = 0;
+            buf[pos] = stage;
+            pos += 1;
+            buf[pos..pos + 16].copy_from_slice(node_id);
+            pos += 16;
+            buf[pos] = ranges.len() as u8;
+            pos += 1;
+            for range in ranges {
+                let len = range.base.len as usize;
+                buf[pos] = range.base.len;
+                pos += 1;
+                buf[pos..pos + len].copy_from_slice(&range.base.data[..len]);
+                pos += len;
+                buf[pos] = range.prefix_len;
+                pos += 1;
+            }
+            pos
+        }
+        Message::Close => 0,
+        Message::DataFragment { id, index, count, bytes } => {
+            let mut pos = 0;
+            buf[pos..pos + 2].copy_from_slice(&id.to_be_bytes());
+            pos += 2;
+            buf[pos..pos + 2].copy_from_slice(&index.to_be_bytes());
+            pos += 2;
+            buf[pos..pos + 2].copy_from_slice(&count.to_be_bytes());
+            pos += 2;
+            buf[pos..pos + bytes.len()].copy_from_slice(bytes);
+            pos += bytes.len();
+            pos
+        }
+        Message::Ping(size) | Message::Pong(size) => {
+            let size = size as usize;
+            for b in buf[..size].iter_mut() {
+                *b = 0;
+            }
+            size
+        }
+        Message::NoiseInit { stage, ref public_key } => {
+            buf[0] = stage;
+            buf[1..1 + HANDSHAKE_KEY_LEN].copy_from_slice(public_key);
+            1 + HANDSHAKE_KEY_LEN
+        }
+    }
+}
+
+fn decode_payload<'a>(msg_type: u8, payload: &'a [u8]) -> io::Result<Message<'a>> {
+    match msg_type {
+        MSG_TYPE_DATA => Ok(Message::Data(payload)),
+        MSG_TYPE_PEERS => {
+            let mut pos = 0;
+            let mut addrs = Vec::new();
+            need(payload, pos, 1)?;
+            let count_v4 = payload[pos] as usize;
+            pos += 1;
+            for _ in 0..count_v4 {
+                need(payload, pos, 6)?;
+                let ip = Ipv4Addr::new(payload[pos], payload[pos + 1], payload[pos + 2], payload[pos + 3]);
+                let port = u16::from_be_bytes([payload[pos + 4], payload[pos + 5]]);
+                pos += 6;
+                addrs.push(SocketAddr::new(IpAddr::V4(ip), port));
+            }
+            need(payload, pos, 1)?;
+            let count_v6 = payload[pos] as usize;
+            pos += 1;
+            for _ in 0..count_v6 {
+                need(payload, pos, 18)?;
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&payload[pos..pos + 16]);
+                let port = u16::from_be_bytes([payload[pos + 16], payload[pos + 17]]);
+                pos += 18;
+                addrs.push(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port));
+            }
+            Ok(Message::Peers(addrs))
+        }
+        MSG_TYPE_INIT => {
+            let mut pos = 0;
+            need(payload, pos, 17)?;
+            let stage = payload[pos];
+            pos += 1;
+            let mut node_id = [0u8; 16];
+            node_id.copy_from_slice(&payload[pos..pos + 16]);
+            pos += 16;
+            need(payload, pos, 1)?;
+            let count = payload[pos];
+            pos += 1;
+            let mut ranges = Vec::new();
+            for _ in 0..count {
+                need(payload, pos, 1)?;
+                let len = payload[pos] as usize;
+                pos += 1;
+                need(payload, pos, len + 1)?;
+                let mut data = [0u8; 16];
+                data[..len].copy_from_slice(&payload[pos..pos + len]);
+                pos += len;
+                let prefix_len = payload[pos];
+                pos += 1;
+                ranges.push(Range { base: Address { data, len: len as u8 }, prefix_len });
+            }
+            Ok(Message::Init(stage, node_id, ranges))
+        }
+        MSG_TYPE_CLOSE => Ok(Message::Close),
+        MSG_TYPE_DATA_FRAGMENT => {
+            need(payload, 0, 6)?;
+            let id = u16::from_be_bytes([payload[0], payload[1]]);
+            let index = u16::from_be_bytes([payload[2], payload[3]]);
+            let count = u16::from_be_bytes([payload[4], payload[5]]);
+            Ok(Message::DataFragment { id, index, count, bytes: &payload[6..] })
+        }
+        MSG_TYPE_PING => Ok(Message::Ping(payload.len() as u16)),
+        MSG_TYPE_PONG => Ok(Message::Pong(payload.len() as u16)),
+        MSG_TYPE_NOISE_INIT => {
+            need(payload, 0, 1 + HANDSHAKE_KEY_LEN)?;
+            let stage = payload[0];
+            let mut public_key = [0u8; HANDSHAKE_KEY_LEN];
+            public_key.copy_from_slice(&payload[1..1 + HANDSHAKE_KEY_LEN]);
+            Ok(Message::NoiseInit { stage, public_key })
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid message type")),
+    }
+}
+
+/// Encodes `msg` (and `options`) into `buf`, encrypting the payload with
+/// `crypto` if it is anything other than `Crypto::None`. Returns the number
+/// of bytes written.
+pub fn encode(options: &mut Options, msg: &Message, buf: &mut [u8], crypto: &mut Crypto) -> io::Result<usize> {
+    buf[0] = MAGIC[0];
+    buf[1] = MAGIC[1];
+    buf[2] = MAGIC[2];
+    buf[3] = VERSION;
+    buf[4] = crypto_method_byte(crypto);
+    buf[5] = 0;
+    let mut pos = FIXED_HEADER_LEN;
+    let mut flags = 0u8;
+    if let Some(network_id) = options.network_id {
+        flags |= FLAG_NETWORK_ID;
+        buf[pos..pos + 8].copy_from_slice(&(network_id as u64).to_be_bytes());
+        pos += 8;
+    }
+    buf[6] = flags;
+    buf[7] = msg.type_id();
+    match *crypto {
+        Crypto::None => {
+            let len = encode_payload(msg, &mut buf[pos..]);
+            Ok(pos + len)
+        }
+        _ => {
+            let header = {
+                let mut h = [0u8; FIXED_HEADER_LEN];
+                h.copy_from_slice(&buf[..FIXED_HEADER_LEN]);
+                h
+            };
+            let nonce_pos = pos;
+            let plain_pos = nonce_pos + NONCE_LEN;
+            let plain_len = encode_payload(msg, &mut buf[plain_pos..]);
+            let mut nonce = [0u8; NONCE_LEN];
+            let cipher_len = crypto.encrypt(&mut buf[plain_pos..], plain_len, &mut nonce, &header)?;
+            buf[nonce_pos..nonce_pos + NONCE_LEN].copy_from_slice(&nonce);
+            Ok(plain_pos + cipher_len)
+        }
+    }
+}
+
+/// Parses the fixed header out of `buf`, decrypting the payload in place
+/// with `crypto` when the header says it is encrypted, and decodes the
+/// resulting bytes into a `Message`.
+pub fn decode<'a>(buf: &'a mut [u8], crypto: &mut Crypto) -> io::Result<(Options, Message<'a>)> {
+    need(buf, 0, FIXED_HEADER_LEN)?;
+    if buf[0..3] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid protocol"));
+    }
+    if buf[3] != VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid version"));
+    }
+    let crypto_byte = buf[4];
+    if crypto_byte > 3 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid crypto"));
+    }
+    let flags = buf[6];
+    let msg_type = buf[7];
+    let header = {
+        let mut h = [0u8; FIXED_HEADER_LEN];
+        h.copy_from_slice(&buf[..FIXED_HEADER_LEN]);
+        h
+    };
+    let mut pos = FIXED_HEADER_LEN;
+    let mut options = Options::default();
+    if flags & FLAG_NETWORK_ID != 0 {
+        need(buf, pos, 8)?;
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&buf[pos..pos + 8]);
+        options.network_id = Some(u64::from_be_bytes(bytes) as u16);
+        pos += 8;
+    }
+    match *crypto {
+        Crypto::None => {
+            let msg = decode_payload(msg_type, &buf[pos..])?;
+            Ok((options, msg))
+        }
+        _ => {
+            need(buf, pos, NONCE_LEN)?;
+            let mut nonce = [0u8; NONCE_LEN];
+            nonce.copy_from_slice(&buf[pos..pos + NONCE_LEN]);
+            pos += NONCE_LEN;
+            let plain_len = crypto.decrypt(&mut buf[pos..], &nonce, &header)?;
+            let msg = decode_payload(msg_type, &buf[pos..pos + plain_len])?;
+            Ok((options, msg))
+        }
+    }
+}
+
+/// Builds the stage-0 (initiator) or stage-1 (responder) `Message::NoiseInit`
+/// that carries `public_key`, as produced by `Crypto::start_handshake`, so
+/// it can be passed straight to `encode`.
+pub fn encode_handshake_init(stage: u8, public_key: &[u8; HANDSHAKE_KEY_LEN]) -> Message<'static> {
+    Message::NoiseInit { stage, public_key: *public_key }
+}
+
+/// Recovers the ephemeral public key carried by a `Message::NoiseInit` built
+/// by `encode_handshake_init`, after it has come back out of `decode`.
+pub fn decode_handshake_init(msg: &Message) -> io::Result<[u8; HANDSHAKE_KEY_LEN]> {
+    match *msg {
+        Message::NoiseInit { public_key, .. } => Ok(public_key),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "not a handshake message")),
+    }
+}